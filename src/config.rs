@@ -0,0 +1,25 @@
+//! Reads the `fsmonitor.exclude` multi-value git config key, which lets
+//! users exclude more than `.git` (build output, `target/`, vendored trees,
+//! etc.) without passing `--exclude` on every invocation.
+
+use std::path::Path;
+use std::process::Command;
+
+use failure::*;
+
+pub fn excludes(work_tree: &Path) -> Fallible<Vec<String>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(work_tree)
+        .args(&["config", "--get-all", "fsmonitor.exclude"])
+        .output()
+        .context("Couldn't run git config")?;
+
+    // `git config --get-all` exits 1 when the key isn't set; that's not an error.
+    if !output.status.success() {
+        return Ok(Vec::new());
+    }
+
+    let stdout = String::from_utf8(output.stdout).context("git config output wasn't valid UTF-8")?;
+    Ok(stdout.lines().map(String::from).collect())
+}
@@ -0,0 +1,282 @@
+//! Persistent Watchman subscription daemon.
+//!
+//! Git re-invokes the hook as a brand new process on every `git status`, so
+//! each call otherwise pays full connect + query latency. `run` keeps a
+//! single Watchman subscription open and buffers the changed paths it sees;
+//! `try_query` is what the short-lived hook process calls to answer a
+//! `(version, token)` request from that buffer over a unix socket in `.git/`,
+//! without talking to Watchman itself. If the daemon isn't running, or the
+//! requested token is older than what it has buffered, `try_query` returns
+//! `Ok(None)` and the caller falls back to a direct query.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use failure::*;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use watchman_client::prelude::*;
+
+use crate::watchman::{connect_and_resolve, exclude_expr, NameOnly};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    version: u8,
+    token: String,
+    /// The exclusion globs (git config + `--exclude`) the hook invocation
+    /// computed for itself. Must match the set the daemon subscribed with,
+    /// or its buffered files were filtered differently and answering from
+    /// it would silently hide changes under whatever it additionally
+    /// excludes.
+    excludes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    /// Everything changed since `clock` (or the whole tree, if `fresh`).
+    Delta {
+        clock: String,
+        fresh: bool,
+        files: Vec<String>,
+        warning: Option<String>,
+    },
+    /// The daemon can't answer this request; the hook should fall back to
+    /// querying Watchman directly.
+    Unknown,
+}
+
+/// One subscription push: the clock it left Watchman at, the files it
+/// touched, whether Watchman flagged it as a fresh instance (meaning
+/// `files` isn't a reliable delta and everything before it is moot), and
+/// any warning Watchman attached to the push.
+struct Update {
+    clock: String,
+    files: Vec<String>,
+    fresh: bool,
+    warning: Option<String>,
+}
+
+/// Caps how many subscription pushes `Buffer` keeps before compacting the
+/// oldest ones away, so a long-lived daemon doesn't grow unbounded. A
+/// request whose token predates the compacted prefix just falls back to a
+/// direct query, the same as if the daemon weren't running at all.
+const MAX_BUFFERED_UPDATES: usize = 256;
+
+/// The in-memory log of subscription pushes since the daemon started.
+struct Buffer {
+    start_clock: String,
+    updates: Vec<Update>,
+}
+
+impl Buffer {
+    fn latest_clock(&self) -> &str {
+        self.updates.last().map(|u| u.clock.as_str()).unwrap_or(&self.start_clock)
+    }
+
+    fn push(&mut self, clock: String, files: Vec<String>, fresh: bool, warning: Option<String>) {
+        self.updates.push(Update { clock, files, fresh, warning });
+        if self.updates.len() > MAX_BUFFERED_UPDATES {
+            let dropped = self.updates.remove(0);
+            self.start_clock = dropped.clock;
+        }
+    }
+
+    /// Files changed strictly after `since`, whether Git should treat that
+    /// as "everything changed" instead (a fresh instance occurred in
+    /// between), and the most recent warning Watchman attached, if any.
+    /// Returns `None` if `since` predates what this buffer has recorded, so
+    /// the caller should fall back to a direct query.
+    ///
+    /// This is a best-effort, exact-match lookup: `since` has to be either
+    /// this buffer's starting clock or one of its own previously-returned
+    /// `latest_clock()` values. A token from a direct query answered by a
+    /// different process, or from before this daemon started, won't match
+    /// even though it's chronologically valid — that just falls back to a
+    /// direct query, same as a cold daemon would.
+    fn delta_since(&self, since: &str) -> Option<(Vec<String>, bool, Option<String>)> {
+        let tail = if since == self.start_clock {
+            &self.updates[..]
+        } else {
+            let position = self.updates.iter().position(|u| u.clock == since)?;
+            &self.updates[position + 1..]
+        };
+
+        let warning = tail.iter().rev().find_map(|u| u.warning.clone());
+
+        if tail.iter().any(|u| u.fresh) {
+            // Everything before the most recent fresh instance is moot;
+            // Git is going to rescan the whole tree anyway.
+            return Some((Vec::new(), true, warning));
+        }
+
+        Some((tail.iter().flat_map(|u| u.files.iter().cloned()).collect(), false, warning))
+    }
+}
+
+pub fn socket_path(work_tree: &Path) -> PathBuf {
+    work_tree.join(".git").join("rs-git-fsmonitor.sock")
+}
+
+/// Everything the daemon needs to answer a hook request: the buffered
+/// deltas, and the exclusion globs its subscription was filtered by (so a
+/// hook invocation with a different `--exclude`/`fsmonitor.exclude` set
+/// can be told to fall back instead of silently getting a delta that was
+/// filtered differently than it asked for).
+struct State {
+    buffer: Mutex<Buffer>,
+    excludes: Vec<String>,
+}
+
+/// The answer to a hook request from either the daemon or a direct query.
+pub struct HookAnswer {
+    pub clock: String,
+    pub fresh: bool,
+    pub files: Vec<String>,
+    pub warning: Option<String>,
+}
+
+/// Run the daemon: subscribe to Watchman and serve hook requests over a unix
+/// socket until the process is killed.
+pub async fn run(work_tree: PathBuf, excludes: Vec<String>) -> Fallible<()> {
+    let (client, resolved_root) = connect_and_resolve(&work_tree).await?;
+
+    let (mut subscription, initial) = client
+        .subscribe::<NameOnly>(
+            &resolved_root,
+            SubscribeRequest {
+                expression: Some(exclude_expr(&excludes)),
+                ..Default::default()
+            },
+        )
+        .await
+        .context("Couldn't subscribe to watchman")?;
+
+    let state = Arc::new(State {
+        buffer: Mutex::new(Buffer {
+            start_clock: initial.clock.to_string(),
+            updates: Vec::new(),
+        }),
+        excludes,
+    });
+
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match subscription.next().await {
+                    Ok(SubscriptionData::FilesChanged(result)) => {
+                        let files = result
+                            .files
+                            .into_iter()
+                            .flatten()
+                            .map(|f| f.name.display().to_string())
+                            .collect();
+                        state.buffer.lock().unwrap().push(
+                            result.clock.to_string(),
+                            files,
+                            result.is_fresh_instance,
+                            result.warning,
+                        );
+                    }
+                    Ok(SubscriptionData::Canceled) => break,
+                    Err(e) => {
+                        eprintln!("watchman subscription ended: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    let socket_path = socket_path(&work_tree);
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path).context("Couldn't bind daemon socket")?;
+
+    loop {
+        let (stream, _) = listener.accept().await.context("Couldn't accept daemon connection")?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state).await {
+                eprintln!("daemon connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Same set of globs, regardless of order: git config and `--exclude`
+/// ordering isn't guaranteed to match between the daemon's launch and a
+/// later hook invocation.
+fn same_excludes(a: &[String], b: &[String]) -> bool {
+    let a: std::collections::HashSet<&String> = a.iter().collect();
+    let b: std::collections::HashSet<&String> = b.iter().collect();
+    a == b
+}
+
+async fn handle_connection(stream: UnixStream, state: Arc<State>) -> Fallible<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let line = lines.next_line().await?.ok_or_else(|| format_err!("daemon client closed connection"))?;
+    let request: Request = serde_json::from_str(&line)?;
+
+    let response = {
+        // Version 1 has no comparable clock token; let the hook fall back.
+        // A mismatched exclusion set means our buffered files were
+        // filtered differently than this hook invocation needs.
+        if request.version != 2 || !same_excludes(&request.excludes, &state.excludes) {
+            Response::Unknown
+        } else {
+            let buffer = state.buffer.lock().unwrap();
+            match buffer.delta_since(&request.token) {
+                Some((files, fresh, warning)) => Response::Delta {
+                    clock: buffer.latest_clock().to_string(),
+                    fresh,
+                    files,
+                    warning,
+                },
+                None => Response::Unknown,
+            }
+        }
+    };
+
+    writer.write_all(serde_json::to_string(&response)?.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    Ok(())
+}
+
+/// Ask a running daemon to answer this hook request. `Ok(None)` means the
+/// daemon isn't running, or can't answer (including because its subscription
+/// was filtered by a different exclusion set), and the caller should fall
+/// back to a direct Watchman query.
+pub async fn try_query(
+    work_tree: &Path,
+    version: u8,
+    token: &str,
+    excludes: &[String],
+) -> Fallible<Option<HookAnswer>> {
+    let stream = match UnixStream::connect(socket_path(work_tree)).await {
+        Ok(stream) => stream,
+        Err(_) => return Ok(None),
+    };
+
+    let (reader, mut writer) = stream.into_split();
+    let request = serde_json::to_string(&Request {
+        version,
+        token: token.to_string(),
+        excludes: excludes.to_vec(),
+    })?;
+    writer.write_all(request.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let line = match lines.next_line().await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    match serde_json::from_str(&line)? {
+        Response::Delta { clock, fresh, files, warning } => Ok(Some(HookAnswer { clock, fresh, files, warning })),
+        Response::Unknown => Ok(None),
+    }
+}
@@ -1,10 +1,14 @@
+mod config;
+mod daemon;
+mod trace;
+mod watchman;
+
 use std::env;
-use std::io::Write;
-use std::process::{exit, Command, Stdio};
+use std::process::exit;
 
 use failure::*;
-use serde_json::{json, Value};
 use structopt::StructOpt;
+use crate::watchman::{build_query, connect_and_resolve, query_once, since_clock};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -12,172 +16,135 @@ use structopt::StructOpt;
     about = "Git fsmonitor hook in Rust\nhttps://git-scm.com/docs/githooks#_fsmonitor_watchman"
 )]
 struct Opt {
-    /// The version of the interface
-    version: u8,
+    /// Run a persistent Watchman subscription daemon instead of answering a
+    /// single hook query. Subsequent version 2 hook invocations will use it if
+    /// it's running.
+    #[structopt(long)]
+    daemon: bool,
+
+    /// The version of the interface, either 1 (timestamp) or 2 (opaque token)
+    #[structopt(required_unless = "daemon")]
+    version: Option<u8>,
 
     /// Watchman clockspec, it can be epoch second or clock id
-    token: String,
+    #[structopt(required_unless = "daemon")]
+    token: Option<String>,
+
+    /// Additional glob to exclude, on top of `.git` and `fsmonitor.exclude`
+    /// git config entries. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let opt = Opt::from_args();
 
-    if opt.version != 2 {
+    let git_work_tree = env::current_dir().unwrap_or_else(|e| {
+        eprintln!("Couldn't get working directory: {}", e);
+        exit(1);
+    });
+    let excludes = all_excludes(&git_work_tree, &opt.exclude).unwrap_or_else(|e| {
+        eprintln!("{}", pretty_error(&e));
+        exit(1);
+    });
+
+    if opt.daemon {
+        daemon::run(git_work_tree, excludes).await.unwrap_or_else(|e| {
+            eprintln!("{}", pretty_error(&e));
+            exit(1);
+        });
+        return;
+    }
+
+    let version = opt.version.expect("version is required unless --daemon");
+    if version != 1 && version != 2 {
         eprintln!("unsupported version");
         exit(1);
     }
+    let token = opt.token.expect("token is required unless --daemon");
 
-    query_watchman(opt.token).unwrap_or_else(|e| {
+    query_watchman(version, token, excludes).await.unwrap_or_else(|e| {
         eprintln!("{}", pretty_error(&e));
         exit(1);
     })
 }
 
-fn query_watchman(token: String) -> Fallible<()> {
-    let git_work_tree = env::current_dir().context("Couldn't get working directory")?;
+/// `--exclude` flags on top of the repo's `fsmonitor.exclude` git config entries.
+fn all_excludes(git_work_tree: &std::path::Path, cli_excludes: &[String]) -> Fallible<Vec<String>> {
+    let mut excludes = config::excludes(git_work_tree)?;
+    excludes.extend(cli_excludes.iter().cloned());
+    Ok(excludes)
+}
 
-    let mut watchman = Command::new("watchman")
-        .args(&["-j", "--no-pretty"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("Couldn't start watchman")?;
-
-    {
-        let watchman_query = get_watchman_query(&git_work_tree, token.clone());
-
-        watchman
-            .stdin
-            .as_mut()
-            .expect("child Watchman process's stdin isn't piped")
-            .write_all(watchman_query.to_string().as_bytes())?;
-    }
+async fn query_watchman(version: u8, token: String, excludes: Vec<String>) -> Fallible<()> {
+    let git_work_tree = env::current_dir().context("Couldn't get working directory")?;
 
-    let output = watchman
-        .wait_with_output()
-        .context("Failed to wait on watchman query")?
-        .stdout;
-
-    let response: Value = serde_json::from_str(
-        String::from_utf8(output)
-            .context("Watchman didn't return valid JSON")?
-            .as_str(),
-    )?;
-
-    if let Some(err) = response["error"].as_str() {
-        ensure!(
-            err.contains("unable to resolve root"),
-            "Watchman failed for an unexpected reason {} (token: {})",
-            err,
-            token
-        );
-        match add_to_watchman(&git_work_tree) {
-            Ok(()) => {}
-            Err(e) => bail!(e),
-        }
-        match get_watchman_clock(&git_work_tree) {
-            Ok(clock_id) => {
-                print!("{}\0/\0", clock_id);
-                return Ok(());
+    if version == 2 {
+        if let Some(answer) = daemon::try_query(&git_work_tree, version, &token, &excludes).await? {
+            if let Some(warning) = &answer.warning {
+                eprintln!("{}", warning);
             }
-            Err(e) => bail!(e),
+            trace::log(|| format!("answered from daemon: clock={} fresh={}", answer.clock, answer.fresh));
+            let bytes_written = write_response(version, &answer.clock, answer.fresh, &answer.files);
+            trace::log(|| format!("wrote {} bytes to stdout", bytes_written));
+            return Ok(());
         }
     }
 
-    match response["files"].as_array() {
-        Some(files) => {
-            print!("{}\0", response["clock"].as_str().unwrap_or(""));
-            for file in files {
-                if let Some(filename) = file.as_str() {
-                    print!("{}\0", filename);
-                }
-            }
+    let (client, resolved_root) = connect_and_resolve(&git_work_tree).await?;
+    let since = since_clock(version, &token)?;
+    let request = build_query(since, &excludes);
+    trace::log(|| format!("query: {:#?}", request));
 
-            Ok(())
-        }
-        None => bail!("missing file data"),
+    let result = query_once(&client, &resolved_root, request).await?;
+    trace::log(|| format!("response: {:#?}", result));
+
+    if let Some(warning) = &result.warning {
+        eprintln!("{}", warning);
     }
-}
 
-fn add_to_watchman(worktree: &std::path::Path) -> Fallible<()> {
-    eprintln!("Adding {} to Watchman's watch list", worktree.display());
-
-    let watchman = Command::new("watchman")
-        .args(&[
-            "watch",
-            worktree
-                .to_str()
-                .expect("Working directory isn't valid Unicode"),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("Couldn't start watchman watch")?;
-
-    let output = watchman
-        .wait_with_output()
-        .context("Failed to wait on `watchman watch`")?;
-    ensure!(output.status.success(), "`watchman watch` failed");
-
-    // Return the fast "everything is dirty" indication to Git.
-    // This makes subsequent queries much faster since Git will pass Watchman
-    // a timestamp from _after_ it started.
-    // (When Watchman gets a time before its run,
-    // it conservatively says everything has changed.)
-    print!("/\0");
+    let clock = result.clock.to_string();
+    let files: Vec<String> = result
+        .files
+        .into_iter()
+        .flatten()
+        .map(|f| f.name.display().to_string())
+        .collect();
+    trace::log(|| format!("resolved clock: {}", clock));
+
+    let bytes_written = write_response(version, &clock, result.is_fresh_instance, &files);
+    trace::log(|| format!("wrote {} bytes to stdout", bytes_written));
+
     Ok(())
 }
 
-fn get_watchman_clock(worktree: &std::path::Path) -> Fallible<String> {
-    let watchman = Command::new("watchman")
-        .args(&[
-            "clock",
-            worktree
-                .to_str()
-                .expect("Working directory isn't valid Unicode"),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("Couldn't start watchman clock")?;
-    let output = watchman
-        .wait_with_output()
-        .context("Failed to wait on watchman clock")?
-        .stdout;
-
-    let response: Value = serde_json::from_str(
-        String::from_utf8(output)
-            .context("Watchman didn't return valid JSON")?
-            .as_str(),
-    )?;
-    match response["clock"].as_str() {
-        Some(clock_id) => Ok(String::from(clock_id)),
-        None => bail!("failed to call watchman clock"),
+/// A fresh instance (daemon restart, recrawl, or a `since` token older than
+/// the watch) means `files` is not a reliable delta: Watchman is telling us
+/// to treat everything as changed, same as the official version 2 hook does.
+///
+/// Version 1 has no clock token to round-trip, so Git only expects the
+/// NUL-separated file list in that case.
+fn write_response(version: u8, clock: &str, fresh: bool, files: &[String]) -> usize {
+    if fresh {
+        if version == 1 {
+            print!("/\0");
+            return 2;
+        }
+        print!("{}\0/\0", clock);
+        return clock.len() + 3;
     }
-}
 
-fn get_watchman_query(git_work_tree: &std::path::Path, token: String) -> Value {
-    // the token following `since` expression can be either epoch second as integer or a clock id as string
-    let token_value = if let Some('c') = token.chars().next() {
-        Value::from(token)
-    } else {
-        Value::from(token.parse::<u64>().unwrap_or(0) / 1_000_000_000)
-    };
-    json!(
-        [
-            "query",
-            git_work_tree,
-            {
-                "since": token_value,
-                "fields": ["name"],
-                "expression": [
-                    "not", [
-                        "dirname", ".git"
-                    ]
-                ]
-            }
-        ]
-    )
+    let mut bytes_written = 0;
+    if version == 2 {
+        print!("{}\0", clock);
+        bytes_written += clock.len() + 1;
+    }
+    for file in files {
+        print!("{}\0", file);
+        bytes_written += file.len() + 1;
+    }
+    bytes_written
 }
 
 // Borrowed lovingly from Burntsushi:
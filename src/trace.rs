@@ -0,0 +1,43 @@
+//! Opt-in debug tracing.
+//!
+//! The Perl reference hooks used to dump the outgoing query and the raw
+//! Watchman response to files for debugging, but that was stripped out after
+//! it turned out to break Git's test harness when it leaked onto
+//! stdout/stderr. This does the same thing, but strictly to a log file: set
+//! `RS_GIT_FSMONITOR_DEBUG` to turn it on, and optionally
+//! `RS_GIT_FSMONITOR_DEBUG_LOG` to pick where it's written. stdout stays the
+//! NUL-delimited protocol output either way.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+const DEBUG_VAR: &str = "RS_GIT_FSMONITOR_DEBUG";
+const LOG_PATH_VAR: &str = "RS_GIT_FSMONITOR_DEBUG_LOG";
+const DEFAULT_LOG_PATH: &str = "rs-git-fsmonitor-debug.log";
+
+fn enabled() -> bool {
+    env::var_os(DEBUG_VAR).is_some()
+}
+
+/// Append the result of `message` to the debug log, if tracing is enabled.
+/// `message` is only called when it's on, so this is zero-cost on the
+/// per-`git status` hot path when `RS_GIT_FSMONITOR_DEBUG` is unset. Never
+/// writes to stdout, and never fails the caller: each failed write to the log
+/// is reported to stderr and otherwise ignored.
+pub fn log(message: impl FnOnce() -> String) {
+    if !enabled() {
+        return;
+    }
+
+    let path = env::var(LOG_PATH_VAR).unwrap_or_else(|_| DEFAULT_LOG_PATH.to_string());
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{}", message()));
+
+    if let Err(e) = result {
+        eprintln!("rs-git-fsmonitor: couldn't write debug log to {}: {}", path, e);
+    }
+}
@@ -0,0 +1,93 @@
+//! Thin helpers around the `watchman_client` crate: connecting, resolving the
+//! watch root, and building the query this hook issues (everything under the
+//! work tree except `.git` and any caller-supplied exclusion globs).
+
+use std::path::{Path, PathBuf};
+
+use failure::*;
+use watchman_client::prelude::*;
+
+query_result_type! {
+    pub struct NameOnly {
+        name: NameField,
+    }
+}
+
+/// Connect to the Watchman daemon and resolve (establishing the watch if
+/// needed) the root for `work_tree`.
+pub async fn connect_and_resolve(work_tree: &Path) -> Fallible<(Client, ResolvedRoot)> {
+    let client = Connector::new()
+        .connect()
+        .await
+        .context("Couldn't connect to watchman")?;
+
+    let resolved_root = client
+        .resolve_root(CanonicalPath::canonicalize(work_tree).context("Couldn't canonicalize working directory")?)
+        .await
+        .context("Couldn't resolve watch root")?;
+
+    Ok((client, resolved_root))
+}
+
+/// Build the exclusion expression: `.git` plus every caller-supplied glob,
+/// each turned into its own `not` term and ANDed together. `.git` is always
+/// excluded, even if `excludes` is empty.
+pub fn exclude_expr(excludes: &[String]) -> Expr {
+    let mut terms = vec![Expr::Not(Box::new(Expr::DirName(DirNameTerm {
+        path: PathBuf::from(".git"),
+        depth: None,
+    })))];
+
+    for pattern in excludes {
+        terms.push(Expr::Not(Box::new(Expr::Match(MatchTerm {
+            glob: pattern.clone(),
+            wholename: true,
+            ..Default::default()
+        }))));
+    }
+
+    if terms.len() == 1 {
+        terms.pop().expect("terms has exactly one element")
+    } else {
+        Expr::All(terms)
+    }
+}
+
+/// The `since` clockspec a hook invocation's `(version, token)` maps to.
+///
+/// Version 2 passes either an opaque Watchman clock id (starting with `c`)
+/// or a nanosecond timestamp; anything that isn't a clock id is treated as
+/// nanoseconds and converted down to whole seconds (0 if it doesn't parse).
+/// Version 1 only ever passes a nanosecond timestamp, which Watchman's
+/// `since` expects converted down to whole seconds.
+pub fn since_clock(version: u8, token: &str) -> Fallible<Clock> {
+    if version == 1 {
+        let seconds = token.parse::<u64>().context("version 1 token isn't a timestamp")? / 1_000_000_000;
+        Ok(Clock::Spec(ClockSpec::UnixTimestamp(seconds as i64)))
+    } else if let Some('c') = token.chars().next() {
+        Ok(Clock::Spec(ClockSpec::StringClock(token.to_string())))
+    } else {
+        let seconds = token.parse::<u64>().unwrap_or(0) / 1_000_000_000;
+        Ok(Clock::Spec(ClockSpec::UnixTimestamp(seconds as i64)))
+    }
+}
+
+pub fn build_query(since: Clock, excludes: &[String]) -> QueryRequestCommon {
+    QueryRequestCommon {
+        since: Some(since),
+        expression: Some(exclude_expr(excludes)),
+        ..Default::default()
+    }
+}
+
+pub async fn query_once(
+    client: &Client,
+    root: &ResolvedRoot,
+    request: QueryRequestCommon,
+) -> Fallible<QueryResult<NameOnly>> {
+    client
+        .query::<NameOnly>(root, request)
+        .await
+        .context("Watchman query failed")
+        .map_err(Into::into)
+}